@@ -7,22 +7,38 @@ static HELP_STR : &str = "PING, INFO, USE [db], CREATE [db],
 ADD [ts],[seq],[is_trade],[is_bid],[price],[size];
 BULKADD ...; DDAKLUB
 FLUSH, FLUSHALL, GETALL, GET [count], CLEAR
+UPGRADE [db], UPGRADE ALL
+VERIFY [db], VERIFY ALL
+CONFIG GET, CONFIG SET [key] [value]
 ";
 
 use byteorder::{BigEndian, WriteBytesExt, /*ReadBytesExt*/};
 
 use std::error::Error;
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Write};
 use std::net::TcpListener;
-use std::net::TcpStream;
 use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc;
 use std::thread;
 use std::str;
 use std::fs;
 
+use signal_hook;
+use signal_hook::iterator::Signals;
+
+use rustls;
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerSession, StreamOwned};
+
 use dtf;
 
+/// Current on-disk dtf format version written by `dtf::encode`. Bump this
+/// whenever the encoding changes; files written under an older version are
+/// read fine (`dtf::decode` dispatches on the version byte) but must go
+/// through `UPGRADE` before they can be appended to again.
+const CURRENT_DTF_VERSION : u8 = 2;
+
 /// name: *should* be the filename
 /// in_memory: are the updates read into memory?
 /// size: true number of items
@@ -49,7 +65,37 @@ struct Store {
     folder: String,
     in_memory: bool,
     size: u64,
-    v: Vec<dtf::Update>
+    v: Vec<dtf::Update>,
+    /// Whether the on-disk file (if any) is encrypted. For a store scanned
+    /// from disk this is read from the cleartext header, since the header
+    /// (magic, symbol, size) is always readable without the key; a store
+    /// made by `CREATE` is encrypted iff a key is configured at that time.
+    encrypted: bool,
+    /// Key used to encrypt/decrypt this store's file, if any. Copied in
+    /// from `Settings::encryption_key` whenever a `Store` is constructed.
+    encryption_key: Option<[u8; 32]>,
+    /// Whether `v` holds updates that haven't made it to disk yet. Checked
+    /// by the LRU evictor so it flushes before it evicts.
+    dirty: bool,
+    /// dtf format version of the file on disk, read from its header.
+    /// `CURRENT_DTF_VERSION` for stores that have no file yet or have been
+    /// through `UPGRADE`.
+    version: u8,
+    /// Set when the file's payload didn't match its stored checksum the
+    /// last time it was scanned or `VERIFY`ed. A corrupt store refuses
+    /// `USE`/`GET` instead of risking a panic inside `decode`.
+    corrupt: bool
+}
+
+/// Why a store currently can't be used/read, if any.
+fn store_access_error(store: &Store) -> Option<String> {
+    if store.corrupt {
+        Some(format!("DB `{}` failed checksum verification; run VERIFY to re-check or restore from backup.", store.name))
+    } else if store.encrypted && store.encryption_key.is_none() {
+        Some(format!("DB `{}` is encrypted; no key configured.", store.name))
+    } else {
+        None
+    }
 }
 
 impl Store {
@@ -57,30 +103,104 @@ impl Store {
     fn add(&mut self, new_vec : dtf::Update) {
         self.size = self.size + 1;
         self.v.push(new_vec);
+        self.dirty = true;
     }
 
     /// write items stored in memory into file
     /// If file exists, use append which only appends a filtered set of updates whose timestamp is larger than the old timestamp
     /// If file doesn't exists, simply encode.
-    /// 
+    ///
+    /// Encrypted stores never use `dtf::append`: appending in place would
+    /// mean re-using the nonce already written to the file, so instead the
+    /// store is loaded in full (if it wasn't already) and re-encoded from
+    /// scratch under a fresh nonce. `dtf::encode`/`dtf::decode`/`dtf::append`
+    /// do the actual nonce generation and ChaCha20 keystream XOR; this only
+    /// decides which of them to call.
+    ///
     /// TODO: Need to figure out how to specify symbol (and exchange name).
-    fn flush(&self) -> Option<bool> {
+    fn flush(&mut self) -> Option<bool> {
+        if self.encrypted && self.encryption_key.is_none() {
+            return None;
+        }
         let fname = format!("{}/{}.dtf", self.folder, self.name);
         create_dir_if_not_exist(&self.folder);
-        if Path::new(&fname).exists() {
-            dtf::append(&fname, &self.v);
-            return Some(true);
-        } else {
-            dtf::encode(&fname, &self.name /*XXX*/, &self.v);
+        // Whether to encrypt on this flush is decided by whether the file on disk
+        // actually *is* encrypted, not by whether a key happens to be configured --
+        // a store created before encryption was turned on must stay plaintext even
+        // once the server has a key, or its payload gets XORed into garbage.
+        let key = if self.encrypted { self.encryption_key } else { None };
+        match key {
+            Some(key) => {
+                if Path::new(&fname).exists() && !self.in_memory {
+                    let mut existing = dtf::decode(&fname, Some(key));
+                    existing.append(&mut self.v);
+                    self.v = existing;
+                    self.in_memory = true;
+                }
+                dtf::encode(&fname, &self.name /*XXX*/, &self.v, Some(key));
+                self.version = CURRENT_DTF_VERSION;
+            },
+            None => {
+                if Path::new(&fname).exists() {
+                    // Appending always writes current-version records; doing that to a
+                    // file still in a legacy layout would mix formats, so it has to go
+                    // through UPGRADE first.
+                    if self.version != CURRENT_DTF_VERSION {
+                        return None;
+                    }
+                    dtf::append(&fname, &self.v, None);
+                    self.dirty = false;
+                    return Some(true);
+                } else {
+                    dtf::encode(&fname, &self.name /*XXX*/, &self.v, None);
+                    self.version = CURRENT_DTF_VERSION;
+                }
+            }
         }
+        self.dirty = false;
         Some(true)
     }
 
+    /// Rewrite this store's file in the current dtf format version,
+    /// regardless of what legacy version it was in before. Writes to a
+    /// `.tmp` sibling, fsyncs it, then renames it over the original so a
+    /// crash mid-upgrade never leaves a half-written file in its place.
+    ///
+    /// This can flip `in_memory` to `true` via the `load()` call below, so
+    /// callers must go through `State::upgrade_store` rather than calling
+    /// this directly, or the store becomes invisible to LRU eviction.
+    fn upgrade(&mut self) -> Result<(), String> {
+        let fname = format!("{}/{}.dtf", self.folder, self.name);
+        if !Path::new(&fname).exists() {
+            return Err(format!("No file on disk for DB `{}`.", self.name));
+        }
+        if self.version == CURRENT_DTF_VERSION {
+            return Ok(());
+        }
+        self.load();
+        let tmp_fname = format!("{}.tmp", fname);
+        // Same gating as flush/load: only encrypt if the file actually *is*
+        // encrypted, or a plaintext store gets silently re-encrypted while
+        // `self.encrypted` stays false, desyncing it from its own file.
+        let key = if self.encrypted { self.encryption_key } else { None };
+        dtf::encode(&tmp_fname, &self.name /*XXX*/, &self.v, key);
+        fs::File::open(&tmp_fname).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+        fs::rename(&tmp_fname, &fname).map_err(|e| e.to_string())?;
+        self.version = CURRENT_DTF_VERSION;
+        Ok(())
+    }
+
     /// load items from dtf file
     fn load(&mut self) {
+        if self.encrypted && self.encryption_key.is_none() {
+            return;
+        }
         let fname = format!("{}/{}.dtf", self.folder, self.name);
         if Path::new(&fname).exists() && !self.in_memory {
-            self.v = dtf::decode(&fname);
+            // Only decrypt if the file on disk is actually encrypted -- a
+            // configured key must not be applied to a plaintext store.
+            let key = if self.encrypted { self.encryption_key } else { None };
+            self.v = dtf::decode(&fname, key);
             self.size = self.v.len() as u64;
             self.in_memory = true;
         }
@@ -96,8 +216,27 @@ impl Store {
     fn clear(&mut self) {
         self.v.clear();
         self.in_memory = false;
+        self.dirty = false;
         self.load_size_from_file();
     }
+
+    /// Force a full re-hash of the on-disk file's payload against its stored
+    /// checksum, updating `corrupt` to match. Returns whether it passed. If
+    /// the store is encrypted and no key is configured, the payload can't be
+    /// read to re-hash, so the previous verdict is left untouched.
+    fn verify(&mut self) -> bool {
+        let fname = format!("{}/{}.dtf", self.folder, self.name);
+        if !Path::new(&fname).exists() {
+            self.corrupt = false;
+            return true;
+        }
+        if self.encrypted && self.encryption_key.is_none() {
+            return !self.corrupt;
+        }
+        let ok = dtf::verify_checksum(&fname, self.encryption_key);
+        self.corrupt = !ok;
+        ok
+    }
 }
 
 
@@ -106,30 +245,48 @@ struct State {
     is_adding: bool,
     store: HashMap<String, Store>,
     current_store_name: String,
-    settings: Settings
+    /// Shared with every other connected client's `State` and with the
+    /// SIGHUP reload thread, so a `CONFIG SET` or config-file reload takes
+    /// effect for all of them on their next check, not just this one.
+    settings: Arc<RwLock<Settings>>,
+    /// Store names in access order, oldest first. Rewritten on every
+    /// `add`/`insert`/`get`/load so the front is always the best eviction
+    /// candidate.
+    lru: Vec<String>
 }
 impl State {
     fn insert(&mut self, up: dtf::Update, store_name : &str) {
         let store = self.store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP");
         store.add(up);
+        self.touch(store_name);
     }
 
     fn add(&mut self, up: dtf::Update) {
         let current_store = self.store.get_mut(&self.current_store_name).expect("KEY IS NOT IN HASHMAP");
         current_store.add(up);
+        let name = self.current_store_name.clone();
+        self.touch(&name);
     }
 
     fn autoflush(&mut self) {
+        let (autoflush, flush_interval) = {
+            let settings = self.settings.read().unwrap();
+            (settings.autoflush, settings.flush_interval)
+        };
         let current_store = self.store.get_mut(&self.current_store_name).expect("KEY IS NOT IN HASHMAP");
-        if self.settings.autoflush && current_store.size % self.settings.flush_interval as u64 == 0 {
+        // flush_interval == 0 means autoflush is effectively disabled; treating it as
+        // "flush on every insert" would require dividing by zero below.
+        if autoflush && flush_interval > 0 && current_store.size % flush_interval as u64 == 0 {
             println!("(AUTO) FLUSHING!");
             current_store.flush();
             current_store.load_size_from_file();
         }
     }
 
-    fn get(&self, count : i32) -> Option<Vec<u8>> {
+    fn get(&mut self, count : i32) -> Option<Vec<u8>> {
         let mut bytes : Vec<u8> = Vec::new();
+        let name = self.current_store_name.clone();
+        self.touch(&name);
         let current_store = self.store.get(&self.current_store_name).unwrap();
         if (current_store.size as i32) < count || current_store.size == 0 {
             None
@@ -146,6 +303,86 @@ impl State {
         }
     }
 
+    /// Move `name` to the most-recently-used end of `lru`.
+    fn touch(&mut self, name: &str) {
+        if let Some(pos) = self.lru.iter().position(|n| n == name) {
+            self.lru.remove(pos);
+        }
+        self.lru.push(name.to_owned());
+    }
+
+    /// Upgrade the named store to `CURRENT_DTF_VERSION`, same as the `USE`
+    /// handler: refuse on a `corrupt`/encrypted-without-key store instead of
+    /// feeding a bad file into `decode`, run it past `evict_for` since
+    /// `Store::upgrade` fully `load()`s a legacy store into memory, and
+    /// `touch` it into the LRU list if that load actually happened -- without
+    /// that, `evict_for` (which only walks `lru`) could never evict it back
+    /// out, leaving it permanently resident.
+    fn upgrade_store(&mut self, name: &str) -> Result<(), String> {
+        let (incoming_size, was_in_memory) = match self.store.get(name) {
+            Some(store) => {
+                if let Some(e) = store_access_error(store) {
+                    return Err(e);
+                }
+                (store.size, store.in_memory)
+            },
+            None => return Err(format!("State does not contain {}", name))
+        };
+        self.evict_for(name, incoming_size);
+        self.store.get_mut(name).unwrap().upgrade()?;
+        if !was_in_memory && self.store.get(name).unwrap().in_memory {
+            self.touch(name);
+        }
+        Ok(())
+    }
+
+    /// Sum of `size` across every store currently resident in memory.
+    fn resident_updates(&self) -> u64 {
+        self.store.values().filter(|s| s.in_memory).map(|s| s.size).sum()
+    }
+
+    /// Evict least-recently-used stores (other than `keep` and the current
+    /// store) until loading `incoming_size` more updates would fit under
+    /// `settings.max_resident_updates`. A budget of `0` means unbounded.
+    /// Dirty stores are flushed before they're evicted so unsaved `ADD`s
+    /// aren't lost.
+    ///
+    /// If `keep` is already resident, its weight is already counted in
+    /// `resident_updates()`, so `incoming_size` is dropped to `0` -- loading
+    /// it is about to be a no-op and counting it again would double-charge
+    /// the budget and could evict other stores needlessly.
+    fn evict_for(&mut self, keep: &str, incoming_size: u64) {
+        let budget = self.settings.read().unwrap().max_resident_updates;
+        if budget == 0 {
+            return;
+        }
+        let incoming_size = if self.store.get(keep).map_or(false, |s| s.in_memory) {
+            0
+        } else {
+            incoming_size
+        };
+        let current = self.current_store_name.clone();
+        let mut i = 0;
+        while self.resident_updates() + incoming_size > budget && i < self.lru.len() {
+            let name = self.lru[i].clone();
+            if name == keep || name == current {
+                i += 1;
+                continue;
+            }
+            if let Some(store) = self.store.get_mut(&name) {
+                if store.in_memory {
+                    // A dirty store that can't be flushed (legacy version, or
+                    // encrypted without a key) stays resident.
+                    if store.dirty && store.flush().is_none() {
+                        i += 1;
+                        continue;
+                    }
+                    store.clear();
+                }
+            }
+            self.lru.remove(i);
+        }
+    }
 }
 
 /// Parses a line that looks like 
@@ -198,7 +435,7 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
         "HELP" => (Some(HELP_STR.to_owned()), None, None),
         "INFO" => {
             let info_vec : Vec<String> = state.store.values().map(|store| {
-                format!(r#"{{"name": "{}", "in_memory": {}, "count": {}}}"#, store.name, store.in_memory, store.size)
+                format!(r#"{{"name": "{}", "in_memory": {}, "count": {}, "version": {}, "corrupt": {}}}"#, store.name, store.in_memory, store.size, store.version, store.corrupt)
             }).collect();
 
             (Some(format!("[{}]\n", info_vec.join(", "))), None, None)
@@ -213,11 +450,18 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
         },
         "GET ALL AS JSON" => {
             let current_store = state.store.get(&state.current_store_name).unwrap();
+            if let Some(e) = store_access_error(current_store) {
+                return (None, None, Some(e));
+            }
             let json = dtf::update_vec_to_json(&current_store.v);
             let json = format!("[{}]\n", json);
             (Some(json), None, None)
         },
         "GET ALL" => {
+            let current_store = state.store.get(&state.current_store_name).unwrap();
+            if let Some(e) = store_access_error(current_store) {
+                return (None, None, Some(e));
+            }
             match state.get(-1) {
                 Some(bytes) => (None, Some(bytes), None),
                 None => (None, None, Some("Failed to GET ALL.".to_owned()))
@@ -240,11 +484,39 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
             (Some("1\n".to_owned()), None, None)
         },
         "FLUSH ALL" => {
-            for store in state.store.values() {
+            for store in state.store.values_mut() {
                 store.flush();
             }
             (Some("1\n".to_owned()), None, None)
         },
+        "UPGRADE ALL" => {
+            let names : Vec<String> = state.store.keys().cloned().collect();
+            let mut failures = Vec::new();
+            for name in names {
+                if let Err(e) = state.upgrade_store(&name) {
+                    failures.push(format!("{}: {}", name, e));
+                }
+            }
+            if failures.is_empty() {
+                (Some("1\n".to_owned()), None, None)
+            } else {
+                (None, None, Some(failures.join("; ")))
+            }
+        },
+        "VERIFY ALL" => {
+            let results : Vec<String> = state.store.values_mut().map(|store| {
+                format!("{}: {}", store.name, if store.verify() { "OK" } else { "CORRUPT" })
+            }).collect();
+            (Some(format!("{}\n", results.join("; "))), None, None)
+        },
+        "CONFIG GET" => {
+            let settings = state.settings.read().unwrap();
+            let json = format!(
+                r#"{{"autoflush": {}, "flush_interval": {}, "dtf_folder": "{}", "max_resident_updates": {}}}"#,
+                settings.autoflush, settings.flush_interval, settings.dtf_folder, settings.max_resident_updates
+            );
+            (Some(format!("{}\n", json)), None, None)
+        },
         _ => {
             // bulkadd and add
             if state.is_adding {
@@ -289,12 +561,21 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
             // db commands
             if string.starts_with("CREATE ") {
                 let dbname : &str = &string[7..];
+                let (folder, encryption_key) = {
+                    let settings = state.settings.read().unwrap();
+                    (settings.dtf_folder.clone(), settings.encryption_key)
+                };
                 state.store.insert(dbname.to_owned(), Store {
                     name: dbname.to_owned(),
                     v: Vec::new(),
                     size: 0,
                     in_memory: false,
-                    folder: state.settings.dtf_folder.clone()
+                    folder,
+                    encrypted: encryption_key.is_some(),
+                    encryption_key,
+                    dirty: false,
+                    version: CURRENT_DTF_VERSION,
+                    corrupt: false
                 });
                 (Some(format!("Created DB `{}`.\n", &dbname)), None, None)
             } else
@@ -302,15 +583,66 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
             if string.starts_with("USE ") {
                 let dbname : &str = &string[4..];
                 if state.store.contains_key(dbname) {
+                    let access_error = store_access_error(&state.store[dbname]);
+                    if let Some(e) = access_error {
+                        return (None, None, Some(e));
+                    }
+                    let incoming_size = state.store[dbname].size;
+                    state.evict_for(dbname, incoming_size);
                     state.current_store_name = dbname.to_owned();
                     let current_store = state.store.get_mut(&state.current_store_name).unwrap();
                     current_store.load();
+                    state.touch(dbname);
                     (Some(format!("SWITCHED TO DB `{}`.\n", &dbname)), None, None)
                 } else {
                     (None, None, Some(format!("State does not contain {}", dbname)))
                 }
             } else
 
+            if string.starts_with("UPGRADE ") {
+                let dbname : &str = &string[8..];
+                match state.upgrade_store(dbname) {
+                    Ok(()) => (Some(format!("UPGRADED DB `{}`.\n", dbname)), None, None),
+                    Err(e) => (None, None, Some(e))
+                }
+            } else
+
+            if string.starts_with("VERIFY ") {
+                let dbname : &str = &string[7..];
+                match state.store.get_mut(dbname) {
+                    Some(store) => {
+                        let ok = store.verify();
+                        (Some(format!("{}: {}\n", dbname, if ok { "OK" } else { "CORRUPT" })), None, None)
+                    },
+                    None => (None, None, Some(format!("State does not contain {}", dbname)))
+                }
+            } else
+
+            if string.starts_with("CONFIG SET ") {
+                let rest = &string[11..];
+                let mut parts = rest.splitn(2, ' ');
+                let key = parts.next().unwrap_or("");
+                let value = parts.next().unwrap_or("");
+                let mut settings = state.settings.write().unwrap();
+                match key {
+                    "autoflush" => match value.parse::<bool>() {
+                        Ok(b) => { settings.autoflush = b; (Some("1\n".to_owned()), None, None) },
+                        Err(_) => (None, None, Some(format!("Invalid boolean for autoflush: `{}`", value)))
+                    },
+                    "flush_interval" => match value.parse::<u32>() {
+                        Ok(0) => (None, None, Some("flush_interval must be greater than 0".to_owned())),
+                        Ok(n) => { settings.flush_interval = n; (Some("1\n".to_owned()), None, None) },
+                        Err(_) => (None, None, Some(format!("Invalid integer for flush_interval: `{}`", value)))
+                    },
+                    "dtf_folder" => { settings.dtf_folder = value.to_owned(); (Some("1\n".to_owned()), None, None) },
+                    "max_resident_updates" => match value.parse::<u64>() {
+                        Ok(n) => { settings.max_resident_updates = n; (Some("1\n".to_owned()), None, None) },
+                        Err(_) => (None, None, Some(format!("Invalid integer for max_resident_updates: `{}`", value)))
+                    },
+                    _ => (None, None, Some(format!("Unknown config key `{}`", key)))
+                }
+            } else
+
             // get
             if string.starts_with("GET ") {
                 let num : &str = &string[4..];
@@ -320,7 +652,9 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
                 if string.contains("AS JSON") {
                     let current_store = state.store.get(&state.current_store_name).unwrap();
 
-                    if (current_store.size as i32) <= count || current_store.size == 0 {
+                    if let Some(e) = store_access_error(current_store) {
+                        (None, None, Some(e))
+                    } else if (current_store.size as i32) <= count || current_store.size == 0 {
                         (None, None, Some("Requested too many".to_owned()))
                     } else {
                         let json = dtf::update_vec_to_json(&current_store.v[..count as usize]);
@@ -328,6 +662,10 @@ fn gen_response(string : &str, state: &mut State) -> (Option<String>, Option<Vec
                         (Some(json), None, None)
                     }
                 } else {
+                    let current_store = state.store.get(&state.current_store_name).unwrap();
+                    if let Some(e) = store_access_error(current_store) {
+                        return (None, None, Some(e));
+                    }
                     match state.get(count) {
                         Some(bytes) => (None, Some(bytes), None),
                         None => (None, None, Some(format!("Failed to get {}.", count)))
@@ -348,48 +686,121 @@ fn create_dir_if_not_exist(dtf_folder : &str) {
     }
 }
 
+/// Number of worker threads used to scan the dtf folder in `init_dbs`. A
+/// directory with thousands of files would otherwise be scanned one file at
+/// a time; capping at 8 avoids spawning more threads than helps on a small
+/// folder.
+const SCAN_WORKERS : usize = 8;
+
 /// Iterate through the dtf files in the folder and load some metadata into memory.
 /// Create corresponding Store objects in State.
+///
+/// The filesystem stat + header parse + checksum verification for each file
+/// is independent, so the scan is split across a small worker pool instead
+/// of running serially; workers hand finished `Store`s back over a channel
+/// and the caller's thread, which holds `state`, does all the `HashMap` inserts.
 fn init_dbs(dtf_folder : &str, state: &mut State) {
-    for dtf_file in fs::read_dir(&dtf_folder).unwrap() {
-        let dtf_file = dtf_file.unwrap();
-        let fname_os = dtf_file.file_name();
-        let fname = fname_os.to_str().unwrap();
-        if fname.ends_with(".dtf") {
-            let name = Path::new(&fname_os).file_stem().unwrap().to_str().unwrap();
-            let header_size = dtf::get_size(&format!("{}/{}", dtf_folder, fname));
-            state.store.insert(name.to_owned(), Store {
-                folder: dtf_folder.to_owned(),
-                name: name.to_owned(),
-                v: Vec::new(),
-                size: header_size,
-                in_memory: false
-            });
-        }
+    let encryption_key = state.settings.read().unwrap().encryption_key;
+    let fnames : Vec<String> = fs::read_dir(&dtf_folder).unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_str().unwrap().to_owned())
+        .filter(|fname| fname.ends_with(".dtf"))
+        .collect();
+
+    let queue = Arc::new(Mutex::new(fnames.into_iter()));
+    let (tx, rx) = mpsc::channel();
+    let num_workers = std::cmp::max(1, std::cmp::min(SCAN_WORKERS, queue.lock().unwrap().len()));
+
+    let mut workers = Vec::with_capacity(num_workers);
+    for _ in 0..num_workers {
+        let queue = Arc::clone(&queue);
+        let tx = tx.clone();
+        let dtf_folder = dtf_folder.to_owned();
+        workers.push(thread::spawn(move || {
+            loop {
+                let fname = match queue.lock().unwrap().next() {
+                    Some(fname) => fname,
+                    None => break
+                };
+                let name = Path::new(&fname).file_stem().unwrap().to_str().unwrap().to_owned();
+                let full_path = format!("{}/{}", dtf_folder, fname);
+                let header_size = dtf::get_size(&full_path);
+                let encrypted = dtf::is_encrypted(&full_path);
+                let version = dtf::get_version(&full_path);
+                // Can't verify a payload we can't decrypt; leave it as not-corrupt
+                // rather than failing shut, since the missing key already blocks
+                // USE/GET through `store_access_error`.
+                let corrupt = !(encrypted && encryption_key.is_none())
+                    && !dtf::verify_checksum(&full_path, encryption_key);
+                let store = Store {
+                    folder: dtf_folder.clone(),
+                    name: name.clone(),
+                    v: Vec::new(),
+                    size: header_size,
+                    in_memory: false,
+                    encrypted,
+                    encryption_key,
+                    dirty: false,
+                    version,
+                    corrupt
+                };
+                tx.send((name, store)).expect("init_dbs receiver dropped");
+            }
+        }));
+    }
+    drop(tx);
+
+    for (name, store) in rx {
+        state.store.insert(name, store);
+    }
+    for worker in workers {
+        worker.join().expect("dtf scan worker panicked");
     }
 }
 
-fn init_state(settings: &Settings, dtf_folder: &str) -> State {
+fn init_state(settings: &Arc<RwLock<Settings>>, dtf_folder: &str) -> State {
+    let (default_encryption_key, default_dtf_folder) = {
+        let s = settings.read().unwrap();
+        (s.encryption_key, s.dtf_folder.clone())
+    };
     let mut state = State {
         current_store_name: "default".to_owned(),
         is_adding: false,
         store: HashMap::new(),
-        settings: settings.clone()
+        settings: Arc::clone(settings),
+        lru: Vec::new()
     };
-    let default_file = format!("{}/default.dtf", settings.dtf_folder);
+    let default_file = format!("{}/default.dtf", default_dtf_folder);
     let default_in_memory = !Path::new(&default_file).exists();
+    let default_encrypted = !default_in_memory && dtf::is_encrypted(&default_file);
+    let default_version = if default_in_memory { CURRENT_DTF_VERSION } else { dtf::get_version(&default_file) };
+    let default_corrupt = !default_in_memory
+        && !(default_encrypted && default_encryption_key.is_none())
+        && !dtf::verify_checksum(&default_file, default_encryption_key);
     state.store.insert("default".to_owned(), Store {
         name: "default".to_owned(),
         v: Vec::new(),
         size: 0,
         in_memory: default_in_memory,
         folder: dtf_folder.to_owned(),
+        encrypted: default_encrypted,
+        encryption_key: default_encryption_key,
+        dirty: false,
+        version: default_version,
+        corrupt: default_corrupt
     });
     state
 }
 
-fn handle_client(mut stream: TcpStream, settings : &Settings) {
-    let dtf_folder = &settings.dtf_folder;
+/// A client connection, plaintext or TLS. `handle_client` and the
+/// length-prefixed response framing it writes don't care which; only the
+/// accept loops in `run_server` know whether they handed it a bare
+/// `TcpStream` or a `rustls::StreamOwned` wrapping one.
+trait ClientStream: Read + Write + Send {}
+impl<T: Read + Write + Send> ClientStream for T {}
+
+fn handle_client(mut stream: Box<dyn ClientStream>, settings : Arc<RwLock<Settings>>) {
+    let dtf_folder = settings.read().unwrap().dtf_folder.clone();
     create_dir_if_not_exist(&dtf_folder);
     let mut state = init_state(&settings, &dtf_folder);
     init_dbs(&dtf_folder, &mut state);
@@ -427,33 +838,223 @@ pub struct Settings {
     pub autoflush: bool,
     pub dtf_folder: String,
     pub flush_interval: u32,
+    /// When set, dtf files are encrypted at rest with ChaCha20 under this
+    /// key. An encrypted store refuses `USE`/`GET` while no key is
+    /// configured; the configured key is not compared against the one the
+    /// file was written with, and stores written without encryption stay
+    /// plaintext and readable.
+    pub encryption_key: Option<[u8; 32]>,
+    /// Upper bound on the total number of updates kept resident across all
+    /// in-memory stores. Once a `USE` would push the total over this
+    /// budget, the least-recently-used other store is flushed and cleared
+    /// to make room. `0` disables eviction.
+    pub max_resident_updates: u64,
+    /// PEM-encoded certificate chain for the TLS listener. Both this and
+    /// `tls_key_path` must be set to turn TLS on at all.
+    pub tls_cert_path: Option<String>,
+    /// PEM-encoded private key matching `tls_cert_path`.
+    pub tls_key_path: Option<String>,
+    /// Port for the TLS listener. Required alongside `tls_cert_path`/
+    /// `tls_key_path`; independent of the plaintext `port` passed to
+    /// `run_server` so both can be bound at once.
+    pub tls_port: Option<u16>,
+    /// When set (and TLS is configured), only the TLS listener is bound --
+    /// the plaintext port from `run_server` is not opened at all.
+    pub tls_only: bool,
+}
+
+/// Stretch a user-supplied passphrase into the 256-bit key `Settings::encryption_key`
+/// expects. This is a simple non-reversible mixing function, not a vetted
+/// password-hashing KDF (no salt, no tunable work factor) -- adequate for a
+/// passphrase supplied out-of-band by whoever starts the server, but not a
+/// substitute for something like Argon2 if the passphrase itself is low-entropy.
+pub fn derive_key(passphrase: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    let bytes = passphrase.as_bytes();
+    for (word, chunk) in key.chunks_mut(8).enumerate() {
+        let mut state: u64 = 0xcbf29ce484222325 ^ (word as u64);
+        for (i, &b) in bytes.iter().enumerate() {
+            state ^= b as u64;
+            state = state.wrapping_mul(0x100000001b3);
+            state = state.rotate_left((i % 31) as u32);
+        }
+        chunk.copy_from_slice(&state.to_le_bytes());
+    }
+    key
+}
+
+/// Re-read the handful of hot-reloadable fields (`autoflush`, `flush_interval`,
+/// `dtf_folder`) from a simple `key=value` config file, keeping everything
+/// else (encryption key, resident-update budget) as it was. Unknown or
+/// malformed lines are ignored rather than treated as fatal -- a bad SIGHUP
+/// shouldn't take the server down.
+fn reload_settings_from_file(path: &str, base: &Settings) -> Settings {
+    let mut next = base.clone();
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(_) => return next
+    };
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = match parts.next() { Some(k) => k.trim(), None => continue };
+        let value = match parts.next() { Some(v) => v.trim(), None => continue };
+        match key {
+            "autoflush" => if let Ok(b) = value.parse::<bool>() { next.autoflush = b; },
+            // 0 would divide-by-zero in State::autoflush; ignore it like any other
+            // malformed line rather than letting a bad config file crash the server.
+            "flush_interval" => if let Ok(n) = value.parse::<u32>() { if n > 0 { next.flush_interval = n; } },
+            "dtf_folder" => next.dtf_folder = value.to_owned(),
+            _ => {}
+        }
+    }
+    next
+}
+
+/// Watch for SIGHUP and re-read `config_path` into `settings` each time one
+/// arrives, so `kill -HUP` picks up edits to the config file without
+/// dropping any connected client.
+fn spawn_config_reload_thread(config_path: String, settings: Arc<RwLock<Settings>>, verbosity: u64) {
+    let signals = Signals::new(&[signal_hook::SIGHUP]).expect("failed to register SIGHUP handler");
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let mut current = settings.write().unwrap();
+            *current = reload_settings_from_file(&config_path, &current);
+            if verbosity > 0 {
+                println!("[INFO] Reloaded config from {} (SIGHUP)", config_path);
+            }
+        }
+    });
+}
+
+/// Parse a PEM certificate chain for `rustls::ServerConfig::set_single_cert`.
+fn load_tls_certs(path: &str) -> Vec<Certificate> {
+    let f = fs::File::open(path).unwrap_or_else(|e| panic!("cannot open tls_cert_path `{}`: {}", path, e));
+    rustls::internal::pemfile::certs(&mut BufReader::new(f))
+        .unwrap_or_else(|_| panic!("no PEM certificates found in `{}`", path))
+}
+
+/// Parse a PEM private key (PKCS#8, falling back to RSA) for
+/// `rustls::ServerConfig::set_single_cert`.
+fn load_tls_key(path: &str) -> PrivateKey {
+    let f = fs::File::open(path).unwrap_or_else(|e| panic!("cannot open tls_key_path `{}`: {}", path, e));
+    let mut reader = BufReader::new(f);
+    let pkcs8 = rustls::internal::pemfile::pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if let Some(key) = pkcs8.into_iter().next() {
+        return key;
+    }
+    let f = fs::File::open(path).unwrap_or_else(|e| panic!("cannot open tls_key_path `{}`: {}", path, e));
+    let mut reader = BufReader::new(f);
+    rustls::internal::pemfile::rsa_private_keys(&mut reader).ok()
+        .and_then(|keys| keys.into_iter().next())
+        .unwrap_or_else(|| panic!("no PEM private key found in `{}`", path))
+}
+
+/// Build a `rustls::ServerConfig` from `Settings::tls_cert_path`/`tls_key_path`,
+/// if both are set. Loaded once at startup -- unlike `autoflush` and friends,
+/// TLS material isn't part of the SIGHUP-reloadable config.
+fn build_tls_config(settings: &Settings) -> Option<Arc<rustls::ServerConfig>> {
+    let cert_path = settings.tls_cert_path.as_ref()?;
+    let key_path = settings.tls_key_path.as_ref()?;
+    let certs = load_tls_certs(cert_path);
+    let key = load_tls_key(key_path);
+    let mut config = rustls::ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key).unwrap_or_else(|e| panic!("invalid TLS cert/key: {}", e));
+    Some(Arc::new(config))
+}
+
+/// Accept plaintext connections forever, handing each off to its own
+/// `handle_client` thread.
+fn accept_plain(listener: TcpListener, settings: Arc<RwLock<Settings>>) {
+    for stream in listener.incoming() {
+        let stream = match stream { Ok(s) => s, Err(_) => continue };
+        let settings_for_thread = Arc::clone(&settings);
+        thread::spawn(move || {
+            handle_client(Box::new(stream), settings_for_thread);
+        });
+    }
 }
 
-pub fn run_server(host : &str, port : &str, verbosity : u64, settings: &Settings) {
+/// Accept TLS connections forever, wrapping each accepted `TcpStream` in a
+/// `rustls::ServerSession` before handing it to `handle_client`. The
+/// 0x1/0x0 status byte + u64 length-prefixed response framing is unchanged;
+/// only the bytes underneath are encrypted.
+fn accept_tls(listener: TcpListener, tls_config: Arc<rustls::ServerConfig>, settings: Arc<RwLock<Settings>>) {
+    for stream in listener.incoming() {
+        let stream = match stream { Ok(s) => s, Err(_) => continue };
+        let session = ServerSession::new(&tls_config);
+        let tls_stream = StreamOwned::new(session, stream);
+        let settings_for_thread = Arc::clone(&settings);
+        thread::spawn(move || {
+            handle_client(Box::new(tls_stream), settings_for_thread);
+        });
+    }
+}
+
+pub fn run_server(host : &str, port : &str, verbosity : u64, settings: &Settings, config_path: Option<&str>) {
     let addr = format!("{}:{}", host, port);
+    let tls_config = build_tls_config(settings);
+    let tls_only = settings.tls_only;
+    let tls_port = settings.tls_port;
+    if tls_only && tls_config.is_none() {
+        panic!("tls_only is set but tls_cert_path/tls_key_path are not both configured");
+    }
+    let settings = Arc::new(RwLock::new(settings.clone()));
 
     if verbosity > 1 {
         println!("[DEBUG] Trying to bind to addr: {}", addr);
+        let settings = settings.read().unwrap();
         if settings.autoflush {
             println!("[DEBUG] Autoflush is true: every {} inserts.", settings.flush_interval);
         }
     }
 
-    let listener = match TcpListener::bind(&addr) {
-        Ok(l) => l,
-        Err(e) => panic!(format!("{:?}", e.description()))
+    let plain_listener = if tls_only {
+        None
+    } else {
+        let listener = match TcpListener::bind(&addr) {
+            Ok(l) => l,
+            Err(e) => panic!(format!("{:?}", e.description()))
+        };
+        if verbosity > 0 {
+            println!("Listening on addr: {}", addr);
+        }
+        Some(listener)
     };
 
-    if verbosity > 0 {
-        println!("Listening on addr: {}", addr);
+    let tls_listener = match (&tls_config, tls_port) {
+        (Some(_), Some(tls_port)) => {
+            let tls_addr = format!("{}:{}", host, tls_port);
+            let listener = match TcpListener::bind(&tls_addr) {
+                Ok(l) => l,
+                Err(e) => panic!(format!("{:?}", e.description()))
+            };
+            if verbosity > 0 {
+                println!("Listening (TLS) on addr: {}", tls_addr);
+            }
+            Some(listener)
+        },
+        (Some(_), None) => panic!("tls_cert_path/tls_key_path are set but tls_port is not"),
+        (None, _) => None
+    };
+
+    if let Some(path) = config_path {
+        spawn_config_reload_thread(path.to_owned(), Arc::clone(&settings), verbosity);
     }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let settings_copy = settings.clone();
-        thread::spawn(move || {
-            handle_client(stream, &settings_copy);
-        });
+    match (plain_listener, tls_listener) {
+        (Some(plain), Some(tls)) => {
+            let tls_config = tls_config.unwrap();
+            let settings_for_tls = Arc::clone(&settings);
+            thread::spawn(move || accept_tls(tls, tls_config, settings_for_tls));
+            accept_plain(plain, settings);
+        },
+        (Some(plain), None) => accept_plain(plain, settings),
+        (None, Some(tls)) => accept_tls(tls, tls_config.unwrap(), settings),
+        (None, None) => panic!("no listener configured: tls_only is set but no TLS port is bound")
     }
 }
 
@@ -491,4 +1092,114 @@ fn should_parse_string_okay() {
         size: 7.65064240
     };
     assert_eq!(target1, parse_line(&string1).unwrap());
-}
\ No newline at end of file
+}
+
+#[test]
+fn derive_key_is_deterministic_and_passphrase_sensitive() {
+    assert_eq!(derive_key("hunter2"), derive_key("hunter2"));
+    assert_ne!(derive_key("hunter2"), derive_key("hunter3"));
+}
+
+fn test_settings() -> Settings {
+    Settings {
+        autoflush: true,
+        dtf_folder: "/tmp".to_owned(),
+        flush_interval: 100,
+        encryption_key: None,
+        max_resident_updates: 0,
+        tls_cert_path: None,
+        tls_key_path: None,
+        tls_port: None,
+        tls_only: false
+    }
+}
+
+fn test_store(name: &str) -> Store {
+    Store {
+        name: name.to_owned(),
+        folder: "/tmp".to_owned(),
+        in_memory: true,
+        size: 0,
+        v: Vec::new(),
+        encrypted: false,
+        encryption_key: None,
+        dirty: false,
+        version: CURRENT_DTF_VERSION,
+        corrupt: false
+    }
+}
+
+#[test]
+fn config_set_rejects_zero_flush_interval() {
+    let settings = Arc::new(RwLock::new(test_settings()));
+    let mut state = State {
+        is_adding: false,
+        store: HashMap::new(),
+        current_store_name: "default".to_owned(),
+        settings: Arc::clone(&settings),
+        lru: Vec::new()
+    };
+    state.store.insert("default".to_owned(), test_store("default"));
+
+    let (_, _, err) = gen_response("CONFIG SET flush_interval 0", &mut state);
+    assert!(err.unwrap().contains("greater than 0"));
+    assert_eq!(settings.read().unwrap().flush_interval, 100);
+}
+
+#[test]
+fn reload_settings_from_file_ignores_zero_flush_interval() {
+    let path = format!("{}/tectonic_test_config_{}.txt", std::env::temp_dir().display(), std::process::id());
+    fs::write(&path, "flush_interval=0\n").unwrap();
+    let next = reload_settings_from_file(&path, &test_settings());
+    fs::remove_file(&path).ok();
+    assert_eq!(next.flush_interval, 100);
+}
+
+#[test]
+fn evict_for_does_not_double_count_an_already_resident_keep_store() {
+    let mut settings = test_settings();
+    settings.max_resident_updates = 10;
+    let settings = Arc::new(RwLock::new(settings));
+    let mut state = State {
+        is_adding: false,
+        store: HashMap::new(),
+        current_store_name: "a".to_owned(),
+        settings: Arc::clone(&settings),
+        lru: Vec::new()
+    };
+
+    let mut a = test_store("a");
+    a.size = 6;
+    state.store.insert("a".to_owned(), a);
+    let mut b = test_store("b");
+    b.size = 3;
+    state.store.insert("b".to_owned(), b);
+    state.lru.push("a".to_owned());
+    state.lru.push("b".to_owned());
+
+    // `a` is already resident at size 6 and resident_updates() is already 9
+    // (6 + 3). Re-`USE`ing `a` passes its own size as `incoming_size`; if that
+    // were added on top (9 + 6 = 15 > 10) it would wrongly evict `b` even
+    // though loading `a` again is a no-op.
+    state.evict_for("a", 6);
+
+    assert!(state.store.get("b").unwrap().in_memory);
+}
+
+#[test]
+fn upgrade_store_refuses_a_corrupt_store() {
+    let settings = Arc::new(RwLock::new(test_settings()));
+    let mut state = State {
+        is_adding: false,
+        store: HashMap::new(),
+        current_store_name: "default".to_owned(),
+        settings: Arc::clone(&settings),
+        lru: Vec::new()
+    };
+    let mut corrupt = test_store("corrupt");
+    corrupt.corrupt = true;
+    state.store.insert("corrupt".to_owned(), corrupt);
+
+    let err = state.upgrade_store("corrupt").unwrap_err();
+    assert!(err.contains("failed checksum verification"));
+}